@@ -1,7 +1,16 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::Thread;
+use std::time::{Duration, Instant};
 
 use console::{Style, StyledObject};
 use lazy_static::lazy_static;
+use log::Record;
+use tracing_appender::non_blocking::WorkerGuard;
 use tracing_subscriber::filter::LevelFilter;
 
 mod logging_subscriber;
@@ -13,9 +22,120 @@ lazy_static! {
 	pub static ref LOGGING_WRITER: Arc<Mutex<LoggingWriter>> = Arc::new(Mutex::new(LoggingWriter::default()));
 }
 
+thread_local! {
+	pub(crate) static THREAD_LEVEL: Cell<Option<LevelFilter>> = const { Cell::new(None) };
+	static IN_WRITER: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Clears `IN_WRITER` when dropped, including while unwinding.
+struct InWriter;
+
+impl Drop for InWriter {
+	fn drop(&mut self) {
+		IN_WRITER.with(|in_writer| in_writer.set(false));
+	}
+}
+
+/// Runs `f` with the global writer, clearing the poison left by a user callback that panicked while holding it.
+/// Returns `None` for events emitted by this thread from inside the writer, such as from a
+/// callback or a panic hook, which would otherwise deadlock.
+pub(crate) fn with_logging_writer<R>(f: impl FnOnce(&mut LoggingWriter) -> R) -> Option<R> {
+	if IN_WRITER.with(|in_writer| in_writer.replace(true)) {
+		return None;
+	}
+	let _in_writer = InWriter;
+	let mut writer = LOGGING_WRITER.lock().unwrap_or_else(|err| {
+		LOGGING_WRITER.clear_poison();
+		err.into_inner()
+	});
+	Some(f(&mut writer))
+}
+
 #[derive(Debug, Clone, Default)]
 pub(crate) struct BlockingWriter {}
 
+/// Receives a field's name and value and returns the value to display.
+pub type FieldTransform = Arc<dyn Fn(&str, &str) -> String + Send + Sync>;
+
+/// Computes the styles used to render a single event.
+pub type StyleResolver = Arc<dyn Fn(&FormatCtx) -> StyleSet + Send + Sync>;
+
+/// The event being rendered, as passed to an [`EventFormatter`].
+pub struct FormatCtx<'a> {
+	pub record: &'a Record<'a>,
+	pub fields: &'a [(&'a str, String)],
+	pub now: chrono::DateTime<chrono::Local>,
+}
+
+/// Renders a single event into `out`, including the trailing newline.
+pub trait EventFormatter {
+	fn format(&self, ctx: &FormatCtx, out: &mut String);
+}
+
+#[derive(Clone)]
+pub(crate) struct Callback<T>(pub(crate) T);
+
+impl<T> fmt::Debug for Callback<T> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("Callback")
+	}
+}
+
+#[derive(Clone)]
+pub(crate) struct Sink(pub(crate) Arc<Mutex<dyn Write + Send>>);
+
+impl Sink {
+	/// Locks the output, clearing the poison left by a writer that panicked.
+	pub(crate) fn lock(&self) -> MutexGuard<'_, dyn Write + Send + 'static> {
+		self.0.lock().unwrap_or_else(|err| {
+			self.0.clear_poison();
+			err.into_inner()
+		})
+	}
+}
+
+impl fmt::Debug for Sink {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("Sink")
+	}
+}
+
+/// Holds the worker guard of a non-blocking writer in the builder. Clones start empty,
+/// so the guard stays with the builder it was given to.
+#[derive(Debug, Default)]
+pub(crate) struct GuardSlot(pub(crate) Option<WorkerGuard>);
+
+impl Clone for GuardSlot {
+	fn clone(&self) -> Self {
+		GuardSlot(None)
+	}
+}
+
+/// Handle to the heartbeat thread; dropping it stops the thread.
+#[derive(Debug)]
+pub(crate) struct Heartbeat {
+	pub(crate) stop: Arc<AtomicBool>,
+	pub(crate) thread: Thread,
+}
+
+impl Drop for Heartbeat {
+	fn drop(&mut self) {
+		self.stop.store(true, Ordering::Release);
+		self.thread.unpark();
+	}
+}
+
+/// Identifies an event's rendered body, everything but the timestamp.
+#[derive(Debug)]
+pub(crate) struct RenderKey {
+	pub(crate) level: log::Level,
+	pub(crate) target: String,
+	pub(crate) file: Option<String>,
+	pub(crate) line: Option<u32>,
+	pub(crate) message: String,
+	pub(crate) fields: Vec<(String, String)>,
+}
+
 #[derive(Debug)]
 #[allow(dead_code)]
 pub struct LoggingWriter {
@@ -47,6 +167,40 @@ pub struct LoggingWriter {
 	display_target: bool,
 	display_filename: bool,
 	display_time: bool,
+
+	time_anchor: Option<Duration>,
+	last_anchor: Cell<Option<chrono::DateTime<chrono::Local>>>,
+	syslog_facility: Option<u8>,
+	field_transform: Option<Callback<FieldTransform>>,
+	level_band: Option<(LevelFilter, LevelFilter)>,
+	fields_prefix: Option<String>,
+	output: Option<Sink>,
+	atomic_writes: bool,
+	target_grouping: bool,
+	last_target: RefCell<Option<String>>,
+	time_position: TimePosition,
+	style_resolver: Option<Callback<StyleResolver>>,
+	max_field_value_len: Option<usize>,
+	timestamp_gradient: bool,
+	labeled_message: bool,
+	line_buffered: bool,
+	formatter: Option<Callback<Arc<dyn EventFormatter + Send + Sync>>>,
+	target_tail: Option<usize>,
+	timestamp_mode: TimestampMode,
+	start: chrono::DateTime<chrono::Local>,
+	field_key_styles: HashMap<String, Style>,
+	diagnostics: bool,
+	slow_span_threshold: Option<Duration>,
+	lossy_utf8: bool,
+	worker_guard: Option<WorkerGuard>,
+	env_level: Option<LevelFilter>,
+	runtime_level: Option<LevelFilter>,
+	render_cache: bool,
+	last_render: RefCell<Option<(RenderKey, String)>>,
+	heartbeat: Option<Heartbeat>,
+	last_event: Instant,
+	ansi: Option<bool>,
+	truecolor: bool,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -57,8 +211,32 @@ pub enum LevelOutput {
 	None,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampMode {
+	/// Wall clock time, rendered with the timestamp format
+	Absolute,
+	/// Time elapsed since the subscriber was built, e.g. `12m34s` or `1h02m05s`
+	ElapsedHuman,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimePosition {
+	Prefix,
+	Suffix,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct StyleSet {
+	pub level: Style,
+	pub message: Style,
+	pub timestamp: Style,
+}
+
+/// Created by [`LoggingSubscriberBuilder::build`].
 #[derive(Debug, Clone)]
-pub struct LoggingSubscriberLayer;
+pub struct LoggingSubscriberLayer {
+	slow_spans: bool,
+}
 
 #[derive(Debug, Clone)]
 pub struct LoggingSubscriberBuilder {
@@ -87,6 +265,33 @@ pub struct LoggingSubscriberBuilder {
 	separator: String,
 	timestamp_format: String,
 	format_level: LevelOutput,
+	time_anchor: Option<Duration>,
+	syslog_facility: Option<u8>,
+	field_transform: Option<Callback<FieldTransform>>,
+	level_band: Option<(LevelFilter, LevelFilter)>,
+	fields_prefix: Option<String>,
+	output: Option<Sink>,
+	atomic_writes: bool,
+	target_grouping: bool,
+	time_position: TimePosition,
+	style_resolver: Option<Callback<StyleResolver>>,
+	max_field_value_len: Option<usize>,
+	timestamp_gradient: bool,
+	labeled_message: bool,
+	line_buffered: Option<bool>,
+	formatter: Option<Callback<Arc<dyn EventFormatter + Send + Sync>>>,
+	target_tail: Option<usize>,
+	timestamp_mode: TimestampMode,
+	field_key_styles: HashMap<String, Style>,
+	diagnostics: bool,
+	slow_span_threshold: Option<Duration>,
+	lossy_utf8: bool,
+	worker_guard: GuardSlot,
+	render_cache: bool,
+	heartbeat: Option<Duration>,
+	color_strip: bool,
+	min_level_env_override: bool,
+	env_level: Option<LevelFilter>,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -157,13 +362,91 @@ pub fn is_enabled() -> Result<bool, PoisonError<MutexGuard<'static, LoggingWrite
 	}
 }
 
+/// Re-emits panics as `tracing::error!` events, then runs the previously installed panic hook
+pub fn install_panic_to_tracing() {
+	let previous = std::panic::take_hook();
+	std::panic::set_hook(Box::new(move |info| {
+		let payload = info.payload();
+		let message = match payload.downcast_ref::<&str>() {
+			Some(message) => message.to_string(),
+			None => payload
+				.downcast_ref::<String>()
+				.cloned()
+				.unwrap_or_else(|| "Box<dyn Any>".to_string()),
+		};
+
+		match info.location() {
+			Some(location) => {
+				tracing::error!(
+					panic.file = location.file(),
+					panic.line = location.line(),
+					"panicked: {}",
+					message
+				)
+			}
+			None => tracing::error!("panicked: {}", message),
+		}
+
+		previous(info);
+	}));
+}
+
+/// Sets the runtime level, which takes precedence over `RUST_LOG` (see
+/// [`with_min_level_env_override`](LoggingSubscriberBuilder::with_min_level_env_override)), the min of the
+/// level band and the builder's min level. A level set with [`set_thread_level`] still wins on its thread.
+/// See [`LoggingWriter::effective_level`].
 #[allow(dead_code)]
 pub fn set_level(value: LevelFilter) -> Result<(), PoisonError<MutexGuard<'static, LoggingWriter>>> {
 	match LOGGING_WRITER.lock() {
 		Ok(mut item) => {
-			item.level = value;
+			item.runtime_level = Some(value);
+			Ok(())
+		}
+		Err(err) => Err(err),
+	}
+}
+
+/// Removes the runtime level set with [`set_level`], falling back to `RUST_LOG`, the level band or the builder's min level
+pub fn clear_level() -> Result<(), PoisonError<MutexGuard<'static, LoggingWriter>>> {
+	match LOGGING_WRITER.lock() {
+		Ok(mut item) => {
+			item.runtime_level = None;
 			Ok(())
 		}
 		Err(err) => Err(err),
 	}
 }
+
+/// Returns the level currently in effect on this thread, see [`LoggingWriter::effective_level`]
+pub fn effective_level() -> Result<LevelFilter, PoisonError<MutexGuard<'static, LoggingWriter>>> {
+	match LOGGING_WRITER.lock() {
+		Ok(item) => Ok(item.effective_level()),
+		Err(err) => Err(err),
+	}
+}
+
+/// Stops the heartbeat thread, if any, and flushes pending output. When the subscriber was built
+/// with [`build`](LoggingSubscriberBuilder::build) over a non-blocking writer, this also drops its
+/// worker guard, waiting for queued lines to be written; call it before the program exits.
+pub fn shutdown() -> Result<(), PoisonError<MutexGuard<'static, LoggingWriter>>> {
+	// through with_logging_writer, so a panic hook logging a panicking flush doesn't deadlock
+	let worker_guard = with_logging_writer(|item| {
+		item.heartbeat = None;
+		let _ = item.flush();
+		item.worker_guard.take()
+	})
+	.flatten();
+	// dropped outside the lock, as it blocks until the worker thread is done
+	drop(worker_guard);
+	Ok(())
+}
+
+/// Overrides the level for events emitted from the current thread
+pub fn set_thread_level(value: LevelFilter) {
+	THREAD_LEVEL.with(|level| level.set(Some(value)));
+}
+
+/// Removes the current thread's level override, reverting to the global level
+pub fn clear_thread_level() {
+	THREAD_LEVEL.with(|level| level.set(None));
+}
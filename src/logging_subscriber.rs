@@ -1,56 +1,118 @@
 use std::collections::HashMap;
-use std::fmt;
-use std::ops::DerefMut;
-use std::path::PathBuf;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use console::Style;
 use log::Record;
+use tracing::span::{Attributes, Id};
 use tracing::{Event, Level};
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
 use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
 use tracing_subscriber::Layer;
 
-use crate::{LevelOutput, LoggingSubscriberBuilder, LoggingSubscriberLayer, LoggingWriter, LOGGING_WRITER};
+use crate::logging_writer::file_name;
+use crate::{
+	with_logging_writer, Callback, EventFormatter, FieldTransform, GuardSlot, Heartbeat, LevelOutput,
+	LoggingSubscriberBuilder, LoggingSubscriberLayer, LoggingWriter, Sink, StyleResolver, TimePosition, TimestampMode,
+	LOGGING_WRITER,
+};
+
+/// A recorded value. Byte strings are kept raw until the writer's `lossy_utf8` setting is known.
+enum FieldValue {
+	Text(String),
+	Bytes(Vec<u8>),
+}
+
+impl Default for FieldValue {
+	fn default() -> Self {
+		FieldValue::Text(String::new())
+	}
+}
+
+impl FieldValue {
+	fn render(self, lossy_utf8: bool) -> String {
+		match self {
+			FieldValue::Text(value) => value,
+			FieldValue::Bytes(value) if lossy_utf8 => String::from_utf8_lossy(&value).into_owned(),
+			FieldValue::Bytes(value) => format_args!("b\"{}\"", value.escape_ascii()).to_string(),
+		}
+	}
+}
 
 #[derive(Default)]
-struct ToStringVisitor<'a>(HashMap<&'a str, String>);
+struct ToStringVisitor {
+	message: FieldValue,
+	fields: Vec<(&'static str, FieldValue)>,
+}
 
-impl fmt::Display for ToStringVisitor<'_> {
-	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-		self.0.iter().try_for_each(|(_k, v)| -> fmt::Result { write!(f, "{}", v) })
+impl ToStringVisitor {
+	fn insert(&mut self, field: &tracing::field::Field, value: String) {
+		self.insert_value(field, FieldValue::Text(value));
+	}
+
+	fn insert_value(&mut self, field: &tracing::field::Field, value: FieldValue) {
+		if field.name() == "message" {
+			self.message = value;
+		} else {
+			self.fields.push((field.name(), value));
+		}
 	}
 }
 
-impl<'a> tracing::field::Visit for ToStringVisitor<'a> {
+/// Busy time of a span, stored in its extensions while slow span reporting is enabled.
+#[derive(Default)]
+struct SpanTiming {
+	busy: Duration,
+	entered: Option<Instant>,
+}
+
+impl tracing::field::Visit for ToStringVisitor {
 	fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
-		self.0.insert(field.name(), format_args!("{}", value).to_string());
+		self.insert(field, format_args!("{}", value).to_string());
 	}
 
 	fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
-		self.0.insert(field.name(), format_args!("{}", value).to_string());
+		self.insert(field, format_args!("{}", value).to_string());
 	}
 
 	fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
-		self.0.insert(field.name(), format_args!("{}", value).to_string());
+		self.insert(field, format_args!("{}", value).to_string());
 	}
 
 	fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
-		self.0.insert(field.name(), format_args!("{}", value).to_string());
+		self.insert(field, format_args!("{}", value).to_string());
 	}
 
 	fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
-		self.0.insert(field.name(), format_args!("{}", value).to_string());
+		self.insert(field, format_args!("{}", value).to_string());
 	}
 
 	fn record_error(&mut self, field: &tracing::field::Field, value: &(dyn std::error::Error + 'static)) {
-		self.0.insert(field.name(), format_args!("{}", value).to_string());
+		self.insert(field, format_args!("{}", value).to_string());
+	}
+
+	fn record_bytes(&mut self, field: &tracing::field::Field, value: &[u8]) {
+		self.insert_value(field, FieldValue::Bytes(value.to_vec()));
 	}
 
 	fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
-		self.0.insert(field.name(), format_args!("{:?}", value).to_string());
+		self.insert(field, format_args!("{:?}", value).to_string());
 	}
 }
 
+/// Parses `RUST_LOG` when it holds a single level such as `debug`; per-target directives are ignored.
+pub(crate) fn parse_env_level(value: &str) -> Option<LevelFilter> {
+	value.trim().parse().ok()
+}
+
+/// Returns true if `term` is a terminal that can render ANSI colors.
+pub(crate) fn ansi_supported(term: &console::Term) -> bool {
+	term.is_term() && term.features().colors_supported()
+}
+
 impl Default for LoggingSubscriberBuilder {
 	fn default() -> Self {
 		LoggingSubscriberBuilder {
@@ -75,6 +137,33 @@ impl Default for LoggingSubscriberBuilder {
 			separator: " ".to_string(),
 			timestamp_format: "%H:%M:%S%.3f".to_string(),
 			format_level: LevelOutput::Long,
+			time_anchor: None,
+			syslog_facility: None,
+			field_transform: None,
+			level_band: None,
+			fields_prefix: None,
+			output: None,
+			atomic_writes: true,
+			target_grouping: false,
+			time_position: TimePosition::Prefix,
+			style_resolver: None,
+			max_field_value_len: None,
+			timestamp_gradient: false,
+			labeled_message: false,
+			line_buffered: None,
+			formatter: None,
+			target_tail: None,
+			timestamp_mode: TimestampMode::Absolute,
+			field_key_styles: HashMap::new(),
+			diagnostics: false,
+			slow_span_threshold: None,
+			lossy_utf8: false,
+			worker_guard: GuardSlot::default(),
+			render_cache: false,
+			heartbeat: None,
+			color_strip: false,
+			min_level_env_override: false,
+			env_level: None,
 		}
 	}
 }
@@ -104,39 +193,255 @@ impl From<LoggingSubscriberBuilder> for LoggingWriter {
 		logging.display_filename = value.display_filename;
 		logging.display_time = value.display_time;
 		logging.date_time_style = value.date_time_style;
+		logging.time_anchor = value.time_anchor;
+		logging.syslog_facility = value.syslog_facility;
+		logging.field_transform = value.field_transform;
+		logging.level_band = value.level_band;
+		logging.fields_prefix = value.fields_prefix;
+		logging.line_buffered = value
+			.line_buffered
+			.unwrap_or_else(|| value.output.is_none() && console::Term::stdout().is_term());
+		logging.output = value.output;
+		logging.atomic_writes = value.atomic_writes;
+		logging.target_grouping = value.target_grouping;
+		logging.time_position = value.time_position;
+		logging.style_resolver = value.style_resolver;
+		logging.max_field_value_len = value.max_field_value_len;
+		logging.timestamp_gradient = value.timestamp_gradient;
+		logging.labeled_message = value.labeled_message;
+		logging.formatter = value.formatter;
+		logging.target_tail = value.target_tail;
+		logging.timestamp_mode = value.timestamp_mode;
+		logging.field_key_styles = value.field_key_styles;
+		logging.diagnostics = value.diagnostics;
+		logging.slow_span_threshold = value.slow_span_threshold;
+		logging.lossy_utf8 = value.lossy_utf8;
+		logging.render_cache = value.render_cache;
+		if value.color_strip {
+			// a writer set with `with_writer` is not the terminal
+			let ansi = logging.output.is_none() && ansi_supported(&console::Term::stdout());
+			logging.force_styling(ansi);
+		}
+		logging.env_level = value.env_level;
 		logging
 	}
 }
 
 #[allow(dead_code)]
 impl LoggingSubscriberBuilder {
-	pub fn build(self) -> LoggingSubscriberLayer {
+	/// Installs the configured writer globally. See [`shutdown`](crate::shutdown) for releasing it.
+	pub fn build(mut self) -> LoggingSubscriberLayer {
+		if self.min_level_env_override {
+			self.env_level = std::env::var("RUST_LOG").ok().and_then(|value| parse_env_level(&value));
+		}
+		let heartbeat = self.heartbeat;
+		let worker_guard = self.worker_guard.0.take();
+		let slow_spans = self.slow_span_threshold.is_some();
 		if let Ok(mut item) = LOGGING_WRITER.lock() {
 			*item = self.into();
+			item.heartbeat = heartbeat.map(Heartbeat::spawn);
+			item.worker_guard = worker_guard;
+			item.truecolor = matches!(std::env::var("COLORTERM").as_deref(), Ok("truecolor" | "24bit"));
 		}
 
-		let subscriber = LoggingSubscriberLayer {};
+		let subscriber = LoggingSubscriberLayer { slow_spans };
 		subscriber
 	}
 
+	/// Like [`build`](Self::build), but hands the guard passed to
+	/// [`with_non_blocking_writer`](Self::with_non_blocking_writer) back to the caller,
+	/// so pending lines are flushed when it is dropped.
+	pub fn build_with_guard(mut self) -> (LoggingSubscriberLayer, Option<WorkerGuard>) {
+		let guard = self.worker_guard.0.take();
+		(self.build(), guard)
+	}
+
 	pub fn with_min_level(mut self, value: LevelFilter) -> Self {
 		self.min_level = value;
 		self
 	}
 
+	/// Let a plain level in `RUST_LOG`, such as `debug`, override the min level. It is read by
+	/// [`build`](Self::build); a level set at runtime with [`set_level`](crate::set_level) still wins.
+	pub fn with_min_level_env_override(mut self, value: bool) -> Self {
+		self.min_level_env_override = value;
+		self
+	}
+
+	/// Set the min level from clap-style `-v`/`-q` counts, starting from `WARN`.
+	pub fn with_verbosity_quiet(mut self, verbose: u8, quiet: u8) -> Self {
+		const LEVELS: [LevelFilter; 6] = [
+			LevelFilter::OFF,
+			LevelFilter::ERROR,
+			LevelFilter::WARN,
+			LevelFilter::INFO,
+			LevelFilter::DEBUG,
+			LevelFilter::TRACE,
+		];
+		let index = (2 + i32::from(verbose) - i32::from(quiet)).clamp(0, LEVELS.len() as i32 - 1);
+		self.min_level = LEVELS[index as usize];
+		self
+	}
+
+	/// Print a warning when a span closes after being busy for longer than `value`.
+	pub fn with_slow_span_threshold(mut self, value: Duration) -> Self {
+		self.slow_span_threshold = Some(value);
+		self
+	}
+
+	/// Print, for every event, whether it passed the level filters or why it was dropped.
+	/// These lines are printed even while logging is disabled.
+	pub fn with_diagnostics(mut self, value: bool) -> Self {
+		self.diagnostics = value;
+		self
+	}
+
+	/// Only show events whose severity lies between `min` and `max` (inclusive),
+	/// e.g. `(LevelFilter::INFO, LevelFilter::WARN)`. Takes precedence over the min level,
+	/// while a thread, runtime or `RUST_LOG` level replaces `min`. Reversed bounds are swapped,
+	/// so `min` is always the least severe of the two.
+	pub fn with_level_band(mut self, min: LevelFilter, max: LevelFilter) -> Self {
+		// a more verbose LevelFilter compares greater
+		self.level_band = Some((min.max(max), min.min(max)));
+		self
+	}
+
 	pub fn with_separator(mut self, value: String) -> Self {
 		self.separator = value;
 		self
 	}
+
+	/// Render the message as `msg="..."` so it can be told apart from the structured fields.
+	pub fn with_labeled_message(mut self, value: bool) -> Self {
+		self.labeled_message = value;
+		self
+	}
+
+	/// Truncate each structured field value longer than `value` characters, appending an ellipsis.
+	pub fn with_max_field_value_len(mut self, value: usize) -> Self {
+		self.max_field_value_len = Some(value);
+		self
+	}
+
+	/// Written once between the message and the structured fields, when there are any.
+	/// Defaults to the separator.
+	pub fn with_fields_prefix(mut self, value: String) -> Self {
+		self.fields_prefix = Some(value);
+		self
+	}
+
 	pub fn with_timestamp_format(mut self, value: String) -> Self {
 		self.timestamp_format = value;
 		self
 	}
+
+	pub fn with_timestamp_mode(mut self, value: TimestampMode) -> Self {
+		self.timestamp_mode = value;
+		self
+	}
+
+	pub fn with_time_position(mut self, value: TimePosition) -> Self {
+		self.time_position = value;
+		self
+	}
 	pub fn with_format_level(mut self, value: LevelOutput) -> Self {
 		self.format_level = value;
 		self
 	}
 
+	/// Print the time as a delta relative to the last anchor, emitting a full
+	/// absolute timestamp header line every `value` interval.
+	pub fn with_time_anchor(mut self, value: Duration) -> Self {
+		self.time_anchor = Some(value);
+		self
+	}
+
+	/// Prefix each line with the syslog priority (`<facility * 8 + severity>`) for the given facility.
+	///
+	/// # Panics
+	///
+	/// Panics if the facility is not a valid syslog facility, i.e. not in `0..=23`.
+	pub fn with_syslog_priority(mut self, facility: Option<u8>) -> Self {
+		if let Some(facility) = facility {
+			assert!(facility <= 23, "invalid syslog facility {}, expected 0..=23", facility);
+		}
+		self.syslog_facility = facility;
+		self
+	}
+
+	/// Decode byte string fields as lossy UTF-8 instead of printing them as escaped `b"..."` literals.
+	pub fn with_lossy_utf8(mut self, value: bool) -> Self {
+		self.lossy_utf8 = value;
+		self
+	}
+
+	/// Emit a `-- still running --` info event, with target `heartbeat`, whenever no event has been logged for `value`.
+	/// The event comes from a background thread, so it reaches the global default subscriber.
+	/// The heartbeat stops when the writer is replaced or on [`shutdown`](crate::shutdown).
+	///
+	/// # Panics
+	///
+	/// Panics if `value` is zero.
+	pub fn with_heartbeat(mut self, value: Duration) -> Self {
+		assert!(!value.is_zero(), "the heartbeat interval must not be zero");
+		self.heartbeat = Some(value);
+		self
+	}
+
+	/// Decide once, when building, whether stdout gets colors: they are stripped
+	/// unless stdout is a terminal supporting them, regardless of `console`'s own detection.
+	/// Output to a writer set with [`with_writer`](Self::with_writer) is always plain.
+	pub fn with_color_strip_for_redirected_stdout(mut self, value: bool) -> Self {
+		self.color_strip = value;
+		self
+	}
+
+	/// Reuse the rendered line, minus the timestamp, when the same event repeats consecutively.
+	/// Ignored while a style resolver or field transform is set.
+	pub fn with_render_cache(mut self, value: bool) -> Self {
+		self.render_cache = value;
+		self
+	}
+
+	/// Post-process structured field values before they are rendered.
+	pub fn with_field_transform(mut self, value: FieldTransform) -> Self {
+		self.field_transform = Some(Callback(value));
+		self
+	}
+
+	/// Write to `value` instead of stdout.
+	pub fn with_writer<W>(mut self, value: W) -> Self
+	where
+		W: Write + Send + 'static,
+	{
+		self.output = Some(Sink(Arc::new(Mutex::new(value))));
+		self
+	}
+
+	/// Write through a `tracing_appender` non-blocking writer. Unless the guard is retrieved with
+	/// [`build_with_guard`](Self::build_with_guard), it is kept alive by the subscriber, and
+	/// [`shutdown`](crate::shutdown) must be called before exiting so queued lines are not lost.
+	/// Clones of the builder don't carry the guard: build the one it was given to.
+	pub fn with_non_blocking_writer(self, writer: NonBlocking, guard: WorkerGuard) -> Self {
+		let mut builder = self.with_writer(writer);
+		builder.worker_guard = GuardSlot(Some(guard));
+		builder
+	}
+
+	/// Write each line with a single `write_all` while holding the output lock,
+	/// so lines from different threads never interleave. Enabled by default. When disabled,
+	/// lines are still written in full, but the lock is released between partial writes.
+	pub fn with_atomic_writes(mut self, value: bool) -> Self {
+		self.atomic_writes = value;
+		self
+	}
+
+	/// Flush the output after every line. Defaults to true when writing to a terminal.
+	pub fn with_line_buffered(mut self, value: bool) -> Self {
+		self.line_buffered = Some(value);
+		self
+	}
+
 	pub fn with_default_style<S>(mut self, value: S) -> Self
 	where
 		S: Into<Style>,
@@ -153,6 +458,34 @@ impl LoggingSubscriberBuilder {
 		self
 	}
 
+	/// Shift the timestamp hue with the current second. Colors are truecolor when `COLORTERM` is
+	/// `truecolor` or `24bit` at [`build`](Self::build), and 256-color approximations otherwise.
+	pub fn with_timestamp_gradient(mut self, value: bool) -> Self {
+		self.timestamp_gradient = value;
+		self
+	}
+
+	/// Replace the built-in layout with a custom formatter.
+	pub fn with_formatter(mut self, value: Box<dyn EventFormatter + Send + Sync>) -> Self {
+		self.formatter = Some(Callback(Arc::from(value)));
+		self
+	}
+
+	/// Compute the level, message and timestamp styles of each event from its record and
+	/// structured fields, replacing the per-level styles configured on this builder.
+	pub fn with_style_resolver(mut self, value: StyleResolver) -> Self {
+		self.style_resolver = Some(Callback(value));
+		self
+	}
+
+	pub fn with_field_key_style_for<S>(mut self, name: &str, value: S) -> Self
+	where
+		S: Into<Style>,
+	{
+		self.field_key_styles.insert(name.to_string(), value.into());
+		self
+	}
+
 	pub fn with_level_style_error<S>(mut self, value: S) -> Self
 	where
 		S: Into<Style>,
@@ -263,12 +596,81 @@ impl LoggingSubscriberBuilder {
 		self.display_filename = display_filename;
 		self
 	}
+
+	/// Only display the last `value` `::`-separated components of the target.
+	///
+	/// # Panics
+	///
+	/// Panics if `value` is zero.
+	pub fn with_target_tail(mut self, value: usize) -> Self {
+		assert!(value > 0, "the target tail must keep at least one component");
+		self.target_tail = Some(value);
+		self
+	}
+
+	/// Print a `── target ──` header whenever the event target changes,
+	/// omitting the target column from the lines in between.
+	pub fn with_target_grouping(mut self, target_grouping: bool) -> Self {
+		self.target_grouping = target_grouping;
+		self
+	}
 }
 
+/// Span timings are kept in span extensions, so the subscriber must implement `LookupSpan`,
+/// as `tracing_subscriber::Registry` does.
 impl<S> Layer<S> for LoggingSubscriberLayer
 where
-	S: tracing::Subscriber,
+	S: tracing::Subscriber + for<'a> LookupSpan<'a>,
 {
+	fn on_new_span(&self, _attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+		if !self.slow_spans {
+			return;
+		}
+
+		if let Some(span) = ctx.span(id) {
+			span.extensions_mut().insert(SpanTiming::default());
+		}
+	}
+
+	fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+		if !self.slow_spans {
+			return;
+		}
+
+		if let Some(span) = ctx.span(id) {
+			if let Some(timing) = span.extensions_mut().get_mut::<SpanTiming>() {
+				timing.entered = Some(Instant::now());
+			}
+		}
+	}
+
+	fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+		if !self.slow_spans {
+			return;
+		}
+
+		if let Some(span) = ctx.span(id) {
+			if let Some(timing) = span.extensions_mut().get_mut::<SpanTiming>() {
+				if let Some(entered) = timing.entered.take() {
+					timing.busy += entered.elapsed();
+				}
+			}
+		}
+	}
+
+	fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+		let Some(span) = ctx.span(&id) else {
+			return;
+		};
+
+		let busy = match span.extensions().get::<SpanTiming>() {
+			Some(timing) => timing.busy,
+			None => return,
+		};
+
+		with_logging_writer(|writer| writer.log_slow_span(span.metadata(), busy));
+	}
+
 	fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
 		let mut visitor = ToStringVisitor::default();
 		event.record(&mut visitor);
@@ -281,22 +683,24 @@ where
 			Level::TRACE => log::Level::Trace,
 		};
 
-		let buf = match event.metadata().file() {
-			None => PathBuf::new(),
-			Some(file) => PathBuf::from(file),
-		};
-
-		let filename = buf.file_name().map(|s| s.to_str().unwrap_or("?"));
-
-		let _ = LOGGING_WRITER.lock().unwrap().deref_mut().log(
-			&Record::builder()
-				.args(format_args!("{}", visitor))
-				.level(level.into())
-				.target(event.metadata().target())
-				.file(filename)
-				.line(event.metadata().line())
-				.module_path(event.metadata().module_path())
-				.build(),
-		);
+		with_logging_writer(|writer| {
+			let message = visitor.message.render(writer.lossy_utf8);
+			let fields: Vec<(&str, String)> = visitor
+				.fields
+				.into_iter()
+				.map(|(name, value)| (name, value.render(writer.lossy_utf8)))
+				.collect();
+			writer.log_event(
+				&Record::builder()
+					.args(format_args!("{}", message))
+					.level(level.into())
+					.target(event.metadata().target())
+					.file(event.metadata().file().and_then(file_name))
+					.line(event.metadata().line())
+					.module_path(event.metadata().module_path())
+					.build(),
+				&fields,
+			)
+		});
 	}
 }
@@ -1,15 +1,30 @@
+use chrono::{DateTime, Local, Timelike};
 use console::Style;
+use palette::{FromColor, Hsv, Srgb};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
 use std::fmt::Write as FmtWrite;
 use std::io;
 use std::io::Write;
 use std::ops::DerefMut;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use log::Record;
 use tracing_log::AsLog;
 use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::fmt::MakeWriter;
 
-use crate::{BlockingWriter, LevelOutput, LoggingWriter, LOGGING_WRITER};
+use crate::{
+	with_logging_writer, BlockingWriter, EventFormatter, FormatCtx, Heartbeat, LevelOutput, LoggingWriter, RenderKey,
+	StyleSet, TimePosition, TimestampMode, LOGGING_WRITER, THREAD_LEVEL,
+};
+
+const ANCHOR_TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.3f";
 
 impl Default for LoggingWriter {
 	fn default() -> Self {
@@ -38,6 +53,39 @@ impl Default for LoggingWriter {
 			display_target: false,
 			display_filename: false,
 			display_line_number: false,
+			time_anchor: None,
+			last_anchor: Cell::new(None),
+			syslog_facility: None,
+			field_transform: None,
+			level_band: None,
+			fields_prefix: None,
+			output: None,
+			atomic_writes: true,
+			target_grouping: false,
+			last_target: RefCell::new(None),
+			time_position: TimePosition::Prefix,
+			style_resolver: None,
+			max_field_value_len: None,
+			timestamp_gradient: false,
+			labeled_message: false,
+			line_buffered: false,
+			formatter: None,
+			target_tail: None,
+			timestamp_mode: TimestampMode::Absolute,
+			start: Local::now(),
+			field_key_styles: HashMap::new(),
+			diagnostics: false,
+			slow_span_threshold: None,
+			lossy_utf8: false,
+			worker_guard: None,
+			env_level: None,
+			runtime_level: None,
+			render_cache: false,
+			last_render: RefCell::new(None),
+			heartbeat: None,
+			last_event: Instant::now(),
+			ansi: None,
+			truecolor: false,
 		}
 	}
 }
@@ -45,72 +93,350 @@ impl Default for LoggingWriter {
 impl Write for LoggingWriter {
 	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
 		if self.enabled {
-			io::stdout().write(buf)
+			self.write_output(buf)
 		} else {
 			Ok(0)
 		}
 	}
 
 	fn flush(&mut self) -> io::Result<()> {
-		io::stdout().flush()
+		match &self.output {
+			Some(sink) => sink.lock().flush(),
+			None => io::stdout().flush(),
+		}
 	}
 }
 
 impl LoggingWriter {
 	pub fn log(&mut self, record: &Record) -> io::Result<usize> {
-		if self.level.as_log() >= record.level() {
-			self.write(self.format_event(record).as_bytes())
+		self.log_event(record, &[])
+	}
+
+	pub fn log_event(&mut self, record: &Record, fields: &[(&str, String)]) -> io::Result<usize> {
+		let drop_reason = self.drop_reason(record.level());
+
+		if self.diagnostics {
+			let verdict = match &drop_reason {
+				Some(reason) => format!("dropped ({})", reason),
+				None => "passed".to_string(),
+			};
+			let line = format!("[diagnostics] {} {}: {}\n", record.level(), record.target(), verdict);
+			self.write_line_unmuted(line.as_bytes())?;
+		}
+
+		if drop_reason.is_none() {
+			let ctx = FormatCtx {
+				record,
+				fields,
+				now: Local::now(),
+			};
+			let formatter: &dyn EventFormatter = match &self.formatter {
+				Some(formatter) => formatter.0.as_ref(),
+				None => self,
+			};
+			let mut output = String::new();
+			formatter.format(&ctx, &mut output);
+			let written = self.write_line(output.as_bytes())?;
+			if self.line_buffered {
+				self.flush()?;
+			}
+			self.last_event = Instant::now();
+			Ok(written)
 		} else {
 			Ok(0)
 		}
 	}
 
-	fn format_event(&self, evt: &Record) -> String {
-		let mut output = String::new();
-		let mut default_style = self.default_style.clone();
+	pub(crate) fn log_slow_span(&mut self, metadata: &tracing::Metadata, busy: Duration) -> io::Result<usize> {
+		match self.slow_span_threshold {
+			Some(threshold) if busy > threshold => self.log_event(
+				&Record::builder()
+					.args(format_args!("slow span {} closed", metadata.name()))
+					.level(log::Level::Warn)
+					.target(metadata.target())
+					.file(metadata.file().and_then(file_name))
+					.line(metadata.line())
+					.module_path(metadata.module_path())
+					.build(),
+				&[("busy", format!("{:?}", busy))],
+			),
+			_ => Ok(0),
+		}
+	}
 
-		let (col_style, lev_long, lev_abbr) = match evt.level() {
-			log::Level::Error => {
-				default_style = self.style_error.clone().unwrap_or(default_style);
-				(self.level_style_error.clone(), "ERROR", "E")
-			}
+	fn write_line(&mut self, line: &[u8]) -> io::Result<usize> {
+		if self.enabled {
+			self.write_line_unmuted(line)
+		} else {
+			Ok(0)
+		}
+	}
 
-			log::Level::Warn => {
-				default_style = self.style_warn.clone().unwrap_or(default_style);
-				(self.level_style_warn.clone(), "WARN ", "W")
+	/// Writes `line` even when logging is disabled.
+	fn write_line_unmuted(&self, line: &[u8]) -> io::Result<usize> {
+		if self.atomic_writes {
+			match &self.output {
+				Some(sink) => sink.lock().write_all(line)?,
+				None => io::stdout().lock().write_all(line)?,
 			}
-			log::Level::Info => {
-				default_style = self.style_info.clone().unwrap_or(default_style);
-				(self.level_style_info.clone(), "INFO ", "I")
+		} else {
+			// the output lock is only held for each partial write
+			let mut remaining = line;
+			while !remaining.is_empty() {
+				match self.write_output(remaining) {
+					Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+					Ok(written) => remaining = &remaining[written..],
+					Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+					Err(err) => return Err(err),
+				}
 			}
-			log::Level::Debug => {
-				default_style = self.style_debug.clone().unwrap_or(default_style);
-				(self.level_style_debug.clone(), "DEBUG", "D")
+		}
+		Ok(line.len())
+	}
+
+	fn write_output(&self, buf: &[u8]) -> io::Result<usize> {
+		match &self.output {
+			Some(sink) => sink.lock().write(buf),
+			None => io::stdout().write(buf),
+		}
+	}
+
+	/// Makes every style emit ANSI codes, or never emit them, whatever `console` detects.
+	pub(crate) fn force_styling(&mut self, ansi: bool) {
+		self.ansi = Some(ansi);
+		for style in [
+			&mut self.default_style,
+			&mut self.date_time_style,
+			&mut self.level_style_error,
+			&mut self.level_style_warn,
+			&mut self.level_style_debug,
+			&mut self.level_style_trace,
+			&mut self.level_style_info,
+		]
+		.into_iter()
+		.chain(
+			[
+				&mut self.style_error,
+				&mut self.style_warn,
+				&mut self.style_debug,
+				&mut self.style_trace,
+				&mut self.style_info,
+			]
+			.into_iter()
+			.flatten(),
+		)
+		.chain(self.field_key_styles.values_mut())
+		{
+			*style = style.clone().force_styling(ansi);
+		}
+	}
+
+	/// The least severe level shown, by precedence: the current thread's level, the runtime level,
+	/// `RUST_LOG`, then the min of the level band, or the builder's min level when there is no band.
+	/// A level band's max applies whichever level is in effect.
+	pub fn effective_level(&self) -> LevelFilter {
+		match (self.level_override(), self.level_band) {
+			(Some((level, _)), _) => level,
+			(None, Some((min, _))) => min,
+			(None, None) => self.level,
+		}
+	}
+
+	/// The thread, runtime or `RUST_LOG` level overriding the configured one, with how diagnostics name it.
+	fn level_override(&self) -> Option<(LevelFilter, &'static str)> {
+		match THREAD_LEVEL.with(|thread_level| thread_level.get()) {
+			Some(thread_level) => Some((thread_level, "thread level")),
+			None => self.runtime_level.or(self.env_level).map(|level| (level, "level")),
+		}
+	}
+
+	/// Returns why an event at `level` would be filtered out, or `None` if it passes.
+	pub(crate) fn drop_reason(&self, level: log::Level) -> Option<String> {
+		if !self.enabled {
+			return Some("disabled".to_string());
+		}
+
+		let effective_level = self.effective_level();
+		if level > effective_level.as_log() {
+			return Some(match (self.level_override(), self.level_band) {
+				(Some((_, source)), _) => format!("below {} {}", source, effective_level),
+				(None, Some((min, max))) => format!("outside level band {}..={}", min, max),
+				(None, None) => format!("below level {}", effective_level),
+			});
+		}
+
+		match self.level_band {
+			Some((min, max)) if level < max.as_log() => Some(format!("outside level band {}..={}", min, max)),
+			_ => None,
+		}
+	}
+
+	fn level_styles(&self, level: log::Level) -> StyleSet {
+		let (level_style, style) = match level {
+			log::Level::Error => (&self.level_style_error, &self.style_error),
+			log::Level::Warn => (&self.level_style_warn, &self.style_warn),
+			log::Level::Info => (&self.level_style_info, &self.style_info),
+			log::Level::Debug => (&self.level_style_debug, &self.style_debug),
+			log::Level::Trace => (&self.level_style_trace, &self.style_trace),
+		};
+
+		StyleSet {
+			level: level_style.clone(),
+			message: style.clone().unwrap_or_else(|| self.default_style.clone()),
+			timestamp: self.date_time_style.clone(),
+		}
+	}
+
+	fn shorten_target<'a>(&self, target: &'a str) -> &'a str {
+		match self.target_tail {
+			Some(tail) if tail > 0 => match target.rmatch_indices("::").nth(tail - 1) {
+				Some((index, _)) => &target[index + 2..],
+				None => target,
+			},
+			_ => target,
+		}
+	}
+
+	pub(crate) fn format_event(&self, evt: &Record, fields: &[(&str, String)], now: DateTime<Local>) -> String {
+		let mut output = String::new();
+
+		let ctx = FormatCtx {
+			record: evt,
+			fields,
+			now,
+		};
+		let styles = match (&self.style_resolver, self.ansi) {
+			(Some(resolver), Some(ansi)) => {
+				let styles = (resolver.0)(&ctx);
+				StyleSet {
+					level: styles.level.force_styling(ansi),
+					message: styles.message.force_styling(ansi),
+					timestamp: styles.timestamp.force_styling(ansi),
+				}
 			}
-			log::Level::Trace => {
-				default_style = self.style_trace.clone().unwrap_or(default_style);
-				(self.level_style_trace.clone(), "TRACE", "T")
+			(Some(resolver), None) => (resolver.0)(&ctx),
+			(None, _) => self.level_styles(evt.level()),
+		};
+
+		if self.target_grouping && self.last_target.borrow().as_deref() != Some(evt.target()) {
+			let header = format!("── {} ──", self.shorten_target(evt.target()));
+			let _ = writeln!(&mut output, "{}", self.default_style.apply_to(header));
+			self.last_target.replace(Some(evt.target().to_string()));
+		}
+
+		let timestamp = match (self.time_anchor, self.timestamp_mode) {
+			_ if !self.display_time => None,
+			(Some(interval), _) => {
+				let anchor = match self.last_anchor.get() {
+					Some(anchor) if (now - anchor).to_std().is_ok_and(|elapsed| elapsed < interval) => anchor,
+					_ => {
+						let header = format!("-- {} --", now.format(ANCHOR_TIMESTAMP_FORMAT));
+						let _ = writeln!(&mut output, "{}", self.paint_timestamp(&styles.timestamp, now, &header));
+						self.last_anchor.set(Some(now));
+						now
+					}
+				};
+				Some(format!("+{:.3}s", (now - anchor).num_milliseconds() as f64 / 1000.0))
 			}
+			(None, TimestampMode::ElapsedHuman) => Some(format_elapsed(now - self.start)),
+			(None, TimestampMode::Absolute) => Some(now.format(&self.timestamp_format).to_string()),
 		};
 
-		if self.display_time {
+		if let Some(facility) = self.syslog_facility {
+			let severity = match evt.level() {
+				log::Level::Error => 3,
+				log::Level::Warn => 4,
+				log::Level::Info => 6,
+				log::Level::Debug | log::Level::Trace => 7,
+			};
+			let priority = u16::from(facility) * 8 + severity;
 			let _ = write!(
 				&mut output,
 				"{}",
-				self.date_time_style
-					.apply_to(chrono::Local::now().format(&self.timestamp_format).to_string())
+				self.default_style.apply_to(format!("<{}>", priority))
 			);
 			let _ = write!(&mut output, "{}", self.default_style.apply_to(&self.separator));
 		}
 
+		if let (Some(timestamp), TimePosition::Prefix) = (&timestamp, self.time_position) {
+			let _ = write!(
+				&mut output,
+				"{}",
+				self.paint_timestamp(&styles.timestamp, now, timestamp)
+			);
+			let _ = write!(&mut output, "{}", self.default_style.apply_to(&self.separator));
+		}
+
+		// the resolver and transform may not be pure functions of the event, so their output is never cached
+		if self.render_cache && self.style_resolver.is_none() && self.field_transform.is_none() {
+			let mut last_render = self.last_render.borrow_mut();
+			match last_render.as_ref() {
+				Some((key, body)) if key.matches(evt, fields) => output.push_str(body),
+				_ => {
+					let message = evt.args().to_string();
+					let body = self.format_body(evt, &message, fields, &styles);
+					output.push_str(&body);
+					*last_render = Some((RenderKey::new(evt, message, fields), body));
+				}
+			}
+		} else {
+			output.push_str(&self.format_body(evt, &evt.args().to_string(), fields, &styles));
+		}
+
+		if let (Some(timestamp), TimePosition::Suffix) = (&timestamp, self.time_position) {
+			let _ = write!(&mut output, "{}", self.default_style.apply_to(&self.separator));
+			let _ = write!(
+				&mut output,
+				"{}",
+				self.paint_timestamp(&styles.timestamp, now, timestamp)
+			);
+		}
+
+		output.push('\n');
+		output
+	}
+
+	/// Applies the timestamp style; under the gradient mode, also a color shifting with the current second,
+	/// as truecolor when the terminal supports it and as a 256-color approximation otherwise.
+	fn paint_timestamp(&self, style: &Style, now: DateTime<Local>, text: &str) -> String {
+		if !self.timestamp_gradient {
+			return style.apply_to(text).to_string();
+		}
+
+		let rgb = gradient_rgb(now.second());
+		if self.truecolor && self.ansi.unwrap_or_else(console::colors_enabled) {
+			format!(
+				"\u{1b}[38;2;{};{};{}m{}\u{1b}[0m",
+				rgb.red,
+				rgb.green,
+				rgb.blue,
+				style.apply_to(text)
+			)
+		} else {
+			style.clone().color256(color_cube_index(rgb)).apply_to(text).to_string()
+		}
+	}
+
+	/// Renders the level, location, message and fields of an event.
+	fn format_body(&self, evt: &Record, message: &str, fields: &[(&str, String)], styles: &StyleSet) -> String {
+		let mut output = String::new();
+
+		let (lev_long, lev_abbr) = match evt.level() {
+			log::Level::Error => ("ERROR", "E"),
+			log::Level::Warn => ("WARN ", "W"),
+			log::Level::Info => ("INFO ", "I"),
+			log::Level::Debug => ("DEBUG", "D"),
+			log::Level::Trace => ("TRACE", "T"),
+		};
+
 		match self.format_level {
 			LevelOutput::Abbreviated => {
 				let s = format!("{: ^3}", lev_abbr);
-				let _ = write!(&mut output, "{}", col_style.apply_to(s));
+				let _ = write!(&mut output, "{}", styles.level.apply_to(s));
 				let _ = write!(&mut output, "{}", self.default_style.apply_to(&self.separator));
 			}
 			LevelOutput::Long => {
-				let _ = write!(&mut output, "{}", col_style.apply_to(lev_long));
+				let _ = write!(&mut output, "{}", styles.level.apply_to(lev_long));
 				let _ = write!(&mut output, "{}", self.default_style.apply_to(&self.separator));
 			}
 			_ => {}
@@ -120,8 +446,12 @@ impl LoggingWriter {
 		let mut file_written: bool = false;
 		let mut line_written: bool = false;
 
-		if self.display_target {
-			let _ = write!(&mut output, "{}", self.default_style.apply_to(evt.target()));
+		if self.display_target && !self.target_grouping {
+			let _ = write!(
+				&mut output,
+				"{}",
+				self.default_style.apply_to(self.shorten_target(evt.target()))
+			);
 			target_written = true;
 		}
 
@@ -157,11 +487,159 @@ impl LoggingWriter {
 			let _ = write!(&mut output, "{}", self.default_style.apply_to(": "));
 		}
 
-		let _ = write!(&mut output, "{}\n", default_style.apply_to(format!("{}", evt.args())));
+		if self.labeled_message {
+			let _ = write!(&mut output, "{}", styles.message.apply_to(format!("msg={:?}", message)));
+		} else {
+			let _ = write!(&mut output, "{}", styles.message.apply_to(message));
+		}
+
+		for (index, (name, value)) in fields.iter().enumerate() {
+			let mut value = match &self.field_transform {
+				Some(transform) => (transform.0)(name, value),
+				None => value.clone(),
+			};
+			if let Some((index, _)) = self.max_field_value_len.and_then(|max| value.char_indices().nth(max)) {
+				value.truncate(index);
+				value.push('…');
+			}
+			let gap = match &self.fields_prefix {
+				Some(prefix) if index == 0 => prefix,
+				_ => &self.separator,
+			};
+			let _ = write!(&mut output, "{}", self.default_style.apply_to(gap));
+			let key_style = self.field_key_styles.get(*name).unwrap_or(&self.default_style);
+			let _ = write!(&mut output, "{}", key_style.apply_to(format!("{}=", name)));
+			let _ = write!(&mut output, "{}", styles.message.apply_to(value));
+		}
+
 		output
 	}
 }
 
+impl EventFormatter for LoggingWriter {
+	fn format(&self, ctx: &FormatCtx, out: &mut String) {
+		out.push_str(&self.format_event(ctx.record, ctx.fields, ctx.now));
+	}
+}
+
+impl RenderKey {
+	fn new(evt: &Record, message: String, fields: &[(&str, String)]) -> Self {
+		RenderKey {
+			level: evt.level(),
+			target: evt.target().to_string(),
+			file: evt.file().map(str::to_string),
+			line: evt.line(),
+			message,
+			fields: fields.iter().map(|(name, value)| (name.to_string(), value.clone())).collect(),
+		}
+	}
+
+	/// Compares the key with an event without allocating.
+	fn matches(&self, evt: &Record, fields: &[(&str, String)]) -> bool {
+		self.level == evt.level()
+			&& self.target == evt.target()
+			&& self.file.as_deref() == evt.file()
+			&& self.line == evt.line()
+			&& self.fields.len() == fields.len()
+			&& self
+				.fields
+				.iter()
+				.zip(fields)
+				.all(|((name, value), (other_name, other_value))| name == other_name && value == other_value)
+			&& renders_to(evt.args(), &self.message)
+	}
+}
+
+/// Returns true if `args` renders exactly to `expected`, without allocating.
+fn renders_to(args: &fmt::Arguments, expected: &str) -> bool {
+	struct Matcher<'a>(&'a str);
+
+	impl fmt::Write for Matcher<'_> {
+		fn write_str(&mut self, value: &str) -> fmt::Result {
+			match self.0.strip_prefix(value) {
+				Some(rest) => {
+					self.0 = rest;
+					Ok(())
+				}
+				None => Err(fmt::Error),
+			}
+		}
+	}
+
+	let mut matcher = Matcher(expected);
+	fmt::write(&mut matcher, *args).is_ok() && matcher.0.is_empty()
+}
+
+impl Heartbeat {
+	/// Spawns a thread emitting a `heartbeat` info event whenever [`LOGGING_WRITER`] has been idle for `interval`.
+	/// The thread has no scoped subscriber, so the event goes to the global default one.
+	pub(crate) fn spawn(interval: Duration) -> Self {
+		let stop = Arc::new(AtomicBool::new(false));
+		let stopped = stop.clone();
+		let handle = thread::Builder::new()
+			.name("logging-heartbeat".to_string())
+			.spawn(move || {
+				let mut wait = interval;
+				loop {
+					thread::park_timeout(wait);
+					// checked under the lock, since the writer is replaced while holding it
+					let idle = with_logging_writer(|writer| {
+						(!stopped.load(Ordering::Acquire)).then(|| writer.last_event.elapsed())
+					});
+					let Some(idle) = idle.flatten() else {
+						break;
+					};
+					if idle < interval {
+						wait = interval - idle;
+						continue;
+					}
+					// emitted outside the lock: the subscriber's layers take it themselves
+					if stopped.load(Ordering::Acquire) {
+						break;
+					}
+					tracing::info!(target: "heartbeat", "-- still running --");
+					wait = interval;
+				}
+			})
+			.expect("failed to spawn the heartbeat thread");
+
+		Heartbeat {
+			stop,
+			thread: handle.thread().clone(),
+		}
+	}
+}
+
+/// The last component of a source path, as shown in the `<file:line>` column.
+pub(crate) fn file_name(path: &str) -> Option<&str> {
+	Path::new(path).file_name().and_then(|name| name.to_str())
+}
+
+/// Formats a duration compactly, e.g. `7s`, `12m34s` or `1h02m05s`.
+fn format_elapsed(elapsed: chrono::Duration) -> String {
+	let seconds = elapsed.num_seconds().max(0);
+	let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+	if hours > 0 {
+		format!("{}h{:02}m{:02}s", hours, minutes, seconds)
+	} else if minutes > 0 {
+		format!("{}m{:02}s", minutes, seconds)
+	} else {
+		format!("{}s", seconds)
+	}
+}
+
+/// Maps a second of the minute to a hue, so the timestamp color cycles once per minute.
+fn gradient_rgb(second: u32) -> Srgb<u8> {
+	let hue = (second % 60) as f32 * 6.0;
+	Srgb::from_color(Hsv::new(hue, 0.5, 0.9)).into_format()
+}
+
+/// The closest color of the 256-color cube.
+fn color_cube_index(rgb: Srgb<u8>) -> u8 {
+	let cube = |value: u8| (u16::from(value) * 5 + 127) / 255;
+	(16 + 36 * cube(rgb.red) + 6 * cube(rgb.green) + cube(rgb.blue)) as u8
+}
+
 impl Write for BlockingWriter {
 	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
 		LOGGING_WRITER.lock().unwrap().deref_mut().write(buf)
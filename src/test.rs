@@ -1,19 +1,143 @@
 #[cfg(test)]
 mod tests {
+	use std::io::Write;
+	use std::sync::{Arc, Mutex};
+	use std::thread;
+	use std::time::Duration;
+
+	use chrono::{DateTime, Local, TimeZone};
+	use console::Style;
+	use log::Record;
 	use termbg::Theme;
 	use tracing::{debug, error, info, info_span, subscriber, trace, warn};
 	use tracing_subscriber::filter::LevelFilter;
 	use tracing_subscriber::prelude::*;
 	use tracing_subscriber::Registry;
 
-	use crate::LoggingSubscriberBuilder;
+	use crate::logging_subscriber::{ansi_supported, parse_env_level};
+	use crate::{
+		clear_level, clear_thread_level, effective_level, install_panic_to_tracing, set_level, set_thread_level,
+		shutdown, EventFormatter, FormatCtx, LoggingSubscriberBuilder, LoggingWriter, StyleSet, TimePosition,
+		TimestampMode, LOGGING_WRITER,
+	};
+
+	/// Sink counting how many times it has been flushed.
+	#[derive(Clone, Default)]
+	struct FlushCountingSink(Arc<Mutex<usize>>);
+
+	impl Write for FlushCountingSink {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			Ok(buf.len())
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			*self.0.lock().unwrap() += 1;
+			Ok(())
+		}
+	}
+
+	struct ArrowFormatter;
+
+	impl EventFormatter for ArrowFormatter {
+		fn format(&self, ctx: &FormatCtx, out: &mut String) {
+			out.push_str(&format!("{} -> {}\n", ctx.record.target(), ctx.record.args()));
+		}
+	}
+
+	/// Layer collecting the message of every event it receives.
+	#[derive(Clone, Default)]
+	struct CapturingLayer(Arc<Mutex<Vec<(tracing::Level, String)>>>);
+
+	impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for CapturingLayer {
+		fn on_event(&self, event: &tracing::Event<'_>, _ctx: tracing_subscriber::layer::Context<'_, S>) {
+			struct MessageVisitor(String);
+
+			impl tracing::field::Visit for MessageVisitor {
+				fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+					if field.name() == "message" {
+						self.0 = format!("{:?}", value);
+					}
+				}
+			}
+
+			let mut visitor = MessageVisitor(String::new());
+			event.record(&mut visitor);
+			self.0.lock().unwrap().push((*event.metadata().level(), visitor.0));
+		}
+	}
+
+	/// Held by tests that replace the global writer.
+	static GLOBAL_WRITER: Mutex<()> = Mutex::new(());
+
+	/// In-memory sink accepting at most a few bytes per `write` call.
+	#[derive(Clone, Default)]
+	struct ChunkedSink(Arc<Mutex<Vec<u8>>>);
+
+	impl Write for ChunkedSink {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			let len = buf.len().min(4);
+			self.0.lock().unwrap().extend_from_slice(&buf[..len]);
+			Ok(len)
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	/// Runs `f` with `install_panic_to_tracing` active, then restores the previous panic hook.
+	fn with_panic_to_tracing<R>(f: impl FnOnce() -> R) -> R {
+		let previous = Arc::new(std::panic::take_hook());
+		let forward = previous.clone();
+		std::panic::set_hook(Box::new(move |info| forward(info)));
+		install_panic_to_tracing();
+
+		let result = f();
+		drop(std::panic::take_hook());
+		std::panic::set_hook(Box::new(move |info| previous(info)));
+		result
+	}
+
+	fn format_info(writer: &LoggingWriter, message: &str, now: DateTime<Local>) -> String {
+		format_info_with_fields(writer, message, &[], now)
+	}
+
+	fn format_info_with_fields(
+		writer: &LoggingWriter,
+		message: &str,
+		fields: &[(&str, String)],
+		now: DateTime<Local>,
+	) -> String {
+		format_target(writer, "test", message, fields, now)
+	}
+
+	fn format_target(
+		writer: &LoggingWriter,
+		target: &str,
+		message: &str,
+		fields: &[(&str, String)],
+		now: DateTime<Local>,
+	) -> String {
+		console::set_colors_enabled(false);
+		writer.format_event(
+			&Record::builder()
+				.args(format_args!("{}", message))
+				.level(log::Level::Info)
+				.target(target)
+				.build(),
+			fields,
+			now,
+		)
+	}
 
 	#[test]
 	fn test_simple() {
+		let _guard = GLOBAL_WRITER.lock().unwrap_or_else(|err| err.into_inner());
 		let registry = Registry::default();
 		let term_subscriber = LoggingSubscriberBuilder::default().with_min_level(LevelFilter::TRACE).build();
 		let subscriber = registry.with(term_subscriber);
-		subscriber::set_global_default(subscriber).unwrap();
+		// test_heartbeat may have installed an equivalent one already
+		let _ = subscriber::set_global_default(subscriber);
 
 		info!("Logging set!");
 		debug!("Debug message");
@@ -40,4 +164,880 @@ mod tests {
 		println!("theme: {:?}", theme);
 		println!("is dark: {:?}", dark_theme);
 	}
+
+	#[test]
+	fn test_time_anchor() {
+		let writer: LoggingWriter = LoggingSubscriberBuilder::default()
+			.with_time_anchor(Duration::from_secs(10))
+			.into();
+		let start = Local.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+
+		let output = format_info(&writer, "first", start);
+		assert_eq!(output, "-- 2024-01-01 12:00:00.000 --\n+0.000s INFO  first\n");
+
+		let output = format_info(&writer, "second", start + chrono::Duration::seconds(5));
+		assert_eq!(output, "+5.000s INFO  second\n");
+
+		let output = format_info(&writer, "third", start + chrono::Duration::seconds(12));
+		assert_eq!(output, "-- 2024-01-01 12:00:12.000 --\n+0.000s INFO  third\n");
+	}
+
+	#[test]
+	fn test_syslog_priority() {
+		// local0 is facility 16, info is severity 6
+		let writer: LoggingWriter = LoggingSubscriberBuilder::default()
+			.with_time(false)
+			.with_syslog_priority(Some(16))
+			.into();
+		let output = format_info(&writer, "hello", Local::now());
+		assert_eq!(output, "<134> INFO  hello\n");
+
+		let writer: LoggingWriter = LoggingSubscriberBuilder::default().with_time(false).into();
+		let output = format_info(&writer, "hello", Local::now());
+		assert_eq!(output, "INFO  hello\n");
+	}
+
+	#[test]
+	#[should_panic(expected = "invalid syslog facility 24")]
+	fn test_syslog_priority_invalid_facility() {
+		let _ = LoggingSubscriberBuilder::default().with_syslog_priority(Some(24));
+	}
+
+	#[test]
+	fn test_field_transform() {
+		let writer: LoggingWriter = LoggingSubscriberBuilder::default()
+			.with_time(false)
+			.with_field_transform(Arc::new(|name, value| match name {
+				"status" => value.to_uppercase(),
+				_ => value.to_string(),
+			}))
+			.into();
+		let fields = [("status", "ok".to_string()), ("code", "abc".to_string())];
+		let output = format_info_with_fields(&writer, "request done", &fields, Local::now());
+		assert_eq!(output, "INFO  request done status=OK code=abc\n");
+	}
+
+	#[test]
+	fn test_level_band() {
+		let writer: LoggingWriter = LoggingSubscriberBuilder::default()
+			.with_min_level(LevelFilter::TRACE)
+			.with_level_band(LevelFilter::INFO, LevelFilter::WARN)
+			.into();
+		assert!(writer.drop_reason(log::Level::Trace).is_some());
+		assert!(writer.drop_reason(log::Level::Debug).is_some());
+		assert!(writer.drop_reason(log::Level::Info).is_none());
+		assert!(writer.drop_reason(log::Level::Warn).is_none());
+		assert!(writer.drop_reason(log::Level::Error).is_some());
+
+		let reversed: LoggingWriter = LoggingSubscriberBuilder::default()
+			.with_level_band(LevelFilter::WARN, LevelFilter::INFO)
+			.into();
+		for level in [log::Level::Debug, log::Level::Info, log::Level::Warn, log::Level::Error] {
+			assert_eq!(reversed.drop_reason(level), writer.drop_reason(level));
+		}
+	}
+
+	#[test]
+	fn test_level_band_precedence() {
+		let mut writer: LoggingWriter = LoggingSubscriberBuilder::default()
+			.with_min_level(LevelFilter::TRACE)
+			.with_level_band(LevelFilter::INFO, LevelFilter::WARN)
+			.into();
+		assert_eq!(writer.effective_level(), LevelFilter::INFO);
+
+		writer.runtime_level = Some(LevelFilter::DEBUG);
+		assert_eq!(writer.effective_level(), LevelFilter::DEBUG);
+		assert!(writer.drop_reason(log::Level::Debug).is_none());
+		assert_eq!(writer.drop_reason(log::Level::Trace).unwrap(), "below level debug");
+		assert_eq!(
+			writer.drop_reason(log::Level::Error).unwrap(),
+			"outside level band info..=warn"
+		);
+
+		let writer = thread::spawn(move || {
+			set_thread_level(LevelFilter::TRACE);
+			assert_eq!(writer.effective_level(), LevelFilter::TRACE);
+			assert!(writer.drop_reason(log::Level::Trace).is_none());
+			assert!(writer.drop_reason(log::Level::Error).is_some());
+			clear_thread_level();
+			writer
+		})
+		.join()
+		.unwrap();
+		assert_eq!(writer.effective_level(), LevelFilter::DEBUG);
+	}
+
+	#[test]
+	fn test_fields_prefix() {
+		let writer: LoggingWriter = LoggingSubscriberBuilder::default()
+			.with_time(false)
+			.with_fields_prefix(" | ".to_string())
+			.into();
+		let fields = [("status", "ok".to_string()), ("code", "200".to_string())];
+		let output = format_info_with_fields(&writer, "request done", &fields, Local::now());
+		assert_eq!(output, "INFO  request done | status=ok code=200\n");
+
+		let output = format_info(&writer, "request done", Local::now());
+		assert_eq!(output, "INFO  request done\n");
+	}
+
+	#[test]
+	fn test_atomic_writes() {
+		console::set_colors_enabled(false);
+		let sink = ChunkedSink::default();
+		let builder = LoggingSubscriberBuilder::default().with_time(false).with_writer(sink.clone());
+
+		let handles: Vec<_> = (0..8)
+			.map(|thread_index| {
+				let mut writer: LoggingWriter = builder.clone().into();
+				thread::spawn(move || {
+					for line_index in 0..200 {
+						let message = format!("thread {} line {}", thread_index, line_index);
+						writer
+							.log(
+								&Record::builder()
+									.args(format_args!("{}", message))
+									.level(log::Level::Info)
+									.build(),
+							)
+							.unwrap();
+					}
+				})
+			})
+			.collect();
+		handles.into_iter().for_each(|handle| handle.join().unwrap());
+
+		let output = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+		let lines: Vec<&str> = output.lines().collect();
+		assert_eq!(lines.len(), 8 * 200);
+		for line in lines {
+			let message = line.strip_prefix("INFO  thread ").unwrap();
+			let (thread_index, line_index) = message.split_once(" line ").unwrap();
+			assert!(thread_index.parse::<usize>().unwrap() < 8);
+			assert!(line_index.parse::<usize>().unwrap() < 200);
+		}
+	}
+
+	#[test]
+	fn test_non_atomic_writes() {
+		console::set_colors_enabled(false);
+		let sink = ChunkedSink::default();
+		let mut writer: LoggingWriter = LoggingSubscriberBuilder::default()
+			.with_time(false)
+			.with_atomic_writes(false)
+			.with_writer(sink.clone())
+			.into();
+		let written = writer
+			.log(
+				&Record::builder()
+					.args(format_args!("longer than a chunk"))
+					.level(log::Level::Info)
+					.build(),
+			)
+			.unwrap();
+
+		let output = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+		assert_eq!(output, "INFO  longer than a chunk\n");
+		assert_eq!(written, output.len());
+	}
+
+	#[test]
+	fn test_panicking_writer() {
+		/// Sink panicking on its first write.
+		#[derive(Clone, Default)]
+		struct PanicOnceSink(Arc<Mutex<Option<Vec<u8>>>>);
+
+		impl Write for PanicOnceSink {
+			fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+				let mut output = self.0.lock().unwrap();
+				match output.as_mut() {
+					Some(output) => output.extend_from_slice(buf),
+					None => {
+						*output = Some(Vec::new());
+						drop(output);
+						panic!("broken writer");
+					}
+				}
+				Ok(buf.len())
+			}
+
+			fn flush(&mut self) -> std::io::Result<()> {
+				Ok(())
+			}
+		}
+
+		console::set_colors_enabled(false);
+		let sink = PanicOnceSink::default();
+		let mut writer: LoggingWriter = LoggingSubscriberBuilder::default()
+			.with_time(false)
+			.with_writer(sink.clone())
+			.into();
+		let record = |message| Record::builder().args(message).level(log::Level::Info).build();
+
+		let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+			writer.log(&record(format_args!("first")))
+		}));
+		assert!(result.is_err());
+		writer.log(&record(format_args!("second"))).unwrap();
+
+		let output = sink.0.lock().unwrap().clone().unwrap();
+		assert_eq!(String::from_utf8(output).unwrap(), "INFO  second\n");
+	}
+
+	#[test]
+	fn test_target_grouping() {
+		let writer: LoggingWriter = LoggingSubscriberBuilder::default()
+			.with_time(false)
+			.with_target(true)
+			.with_target_grouping(true)
+			.into();
+		let now = Local::now();
+
+		assert_eq!(
+			format_target(&writer, "hyper", "first", &[], now),
+			"── hyper ──\nINFO  first\n"
+		);
+		assert_eq!(format_target(&writer, "hyper", "second", &[], now), "INFO  second\n");
+		assert_eq!(
+			format_target(&writer, "app", "third", &[], now),
+			"── app ──\nINFO  third\n"
+		);
+		assert_eq!(
+			format_target(&writer, "hyper", "fourth", &[], now),
+			"── hyper ──\nINFO  fourth\n"
+		);
+	}
+
+	#[test]
+	fn test_time_position_suffix() {
+		let now = Local.with_ymd_and_hms(2024, 1, 1, 12, 30, 15).unwrap();
+
+		let writer: LoggingWriter = LoggingSubscriberBuilder::default().into();
+		assert_eq!(format_info(&writer, "hello", now), "12:30:15.000 INFO  hello\n");
+
+		let writer: LoggingWriter = LoggingSubscriberBuilder::default()
+			.with_time_position(TimePosition::Suffix)
+			.into();
+		assert_eq!(format_info(&writer, "hello", now), "INFO  hello 12:30:15.000\n");
+	}
+
+	#[test]
+	fn test_style_resolver() {
+		let writer: LoggingWriter = LoggingSubscriberBuilder::default()
+			.with_time(false)
+			.with_style_resolver(Arc::new(|ctx| {
+				let slow = ctx.fields.iter().any(|(name, value)| *name == "slow" && value == "true");
+				match (ctx.record.target(), slow) {
+					("db", _) => StyleSet {
+						message: Style::new().red().force_styling(true),
+						..StyleSet::default()
+					},
+					(_, true) => StyleSet {
+						message: Style::new().yellow().force_styling(true),
+						..StyleSet::default()
+					},
+					_ => StyleSet::default(),
+				}
+			}))
+			.into();
+		let now = Local::now();
+
+		assert_eq!(
+			format_target(&writer, "db", "query", &[], now),
+			"INFO  \u{1b}[31mquery\u{1b}[0m\n"
+		);
+		assert_eq!(format_target(&writer, "app", "started", &[], now), "INFO  started\n");
+		assert_eq!(
+			format_target(&writer, "app", "request", &[("slow", "true".to_string())], now),
+			"INFO  \u{1b}[33mrequest\u{1b}[0m slow=\u{1b}[33mtrue\u{1b}[0m\n"
+		);
+	}
+
+	#[test]
+	fn test_max_field_value_len() {
+		let writer: LoggingWriter = LoggingSubscriberBuilder::default()
+			.with_time(false)
+			.with_max_field_value_len(5)
+			.into();
+		let fields = [("blob", "ééééééééé".to_string()), ("id", "12345".to_string())];
+		let output = format_info_with_fields(&writer, "payload", &fields, Local::now());
+		assert_eq!(output, "INFO  payload blob=ééééé… id=12345\n");
+	}
+
+	#[test]
+	fn test_verbosity_quiet() {
+		let level = |verbose, quiet| {
+			LoggingSubscriberBuilder::default()
+				.with_verbosity_quiet(verbose, quiet)
+				.min_level
+		};
+		assert_eq!(level(0, 0), LevelFilter::WARN);
+		assert_eq!(level(2, 1), LevelFilter::INFO);
+		assert_eq!(level(3, 0), LevelFilter::TRACE);
+		assert_eq!(level(10, 0), LevelFilter::TRACE);
+		assert_eq!(level(0, 1), LevelFilter::ERROR);
+		assert_eq!(level(0, 3), LevelFilter::OFF);
+	}
+
+	#[test]
+	fn test_timestamp_gradient() {
+		let writer: LoggingWriter = LoggingSubscriberBuilder::default()
+			.with_date_time_style(Style::new().force_styling(true))
+			.with_timestamp_gradient(true)
+			.into();
+		let now = Local.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+
+		let first = format_info(&writer, "hello", now);
+		let second = format_info(&writer, "hello", now + chrono::Duration::seconds(20));
+		let escape = |output: &str| output.split('m').next().unwrap().to_string();
+		assert!(first.starts_with("\u{1b}[38;5;"));
+		assert_ne!(escape(&first), escape(&second));
+
+		let mut writer: LoggingWriter = LoggingSubscriberBuilder::default().with_timestamp_gradient(true).into();
+		writer.truecolor = true;
+		writer.force_styling(true);
+		let first = format_info(&writer, "hello", now);
+		let second = format_info(&writer, "hello", now + chrono::Duration::seconds(20));
+		assert!(first.starts_with("\u{1b}[38;2;"));
+		assert_ne!(escape(&first), escape(&second));
+
+		writer.force_styling(false);
+		assert_eq!(format_info(&writer, "hello", now), "12:00:00.000 INFO  hello\n");
+	}
+
+	#[test]
+	fn test_panic_to_tracing() {
+		// panics from other tests while the hook is installed are logged through the global writer
+		let _guard = GLOBAL_WRITER.lock().unwrap_or_else(|err| err.into_inner());
+		let layer = CapturingLayer::default();
+		let result = with_panic_to_tracing(|| {
+			subscriber::with_default(Registry::default().with(layer.clone()), || {
+				std::panic::catch_unwind(|| panic!("something broke"))
+			})
+		});
+		assert!(result.is_err());
+
+		let events = layer.0.lock().unwrap();
+		assert_eq!(
+			events.as_slice(),
+			[(tracing::Level::ERROR, "panicked: something broke".to_string())]
+		);
+	}
+
+	#[test]
+	fn test_labeled_message() {
+		let fields = [("status", "ok".to_string())];
+
+		let writer: LoggingWriter = LoggingSubscriberBuilder::default()
+			.with_time(false)
+			.with_labeled_message(true)
+			.into();
+		let output = format_info_with_fields(&writer, "request done", &fields, Local::now());
+		assert_eq!(output, "INFO  msg=\"request done\" status=ok\n");
+
+		let writer: LoggingWriter = LoggingSubscriberBuilder::default().with_time(false).into();
+		let output = format_info_with_fields(&writer, "request done", &fields, Local::now());
+		assert_eq!(output, "INFO  request done status=ok\n");
+	}
+
+	#[test]
+	fn test_line_buffered() {
+		for (line_buffered, expected_flushes) in [(true, 3), (false, 0)] {
+			let sink = FlushCountingSink::default();
+			let mut writer: LoggingWriter = LoggingSubscriberBuilder::default()
+				.with_writer(sink.clone())
+				.with_line_buffered(line_buffered)
+				.into();
+			for _ in 0..3 {
+				writer
+					.log(&Record::builder().args(format_args!("line")).level(log::Level::Info).build())
+					.unwrap();
+			}
+			assert_eq!(*sink.0.lock().unwrap(), expected_flushes);
+		}
+	}
+
+	#[test]
+	fn test_custom_formatter() {
+		let sink = ChunkedSink::default();
+		let mut writer: LoggingWriter = LoggingSubscriberBuilder::default()
+			.with_writer(sink.clone())
+			.with_formatter(Box::new(ArrowFormatter))
+			.into();
+		writer
+			.log(
+				&Record::builder()
+					.args(format_args!("hello"))
+					.level(log::Level::Info)
+					.target("app")
+					.build(),
+			)
+			.unwrap();
+		assert_eq!(
+			String::from_utf8(sink.0.lock().unwrap().clone()).unwrap(),
+			"app -> hello\n"
+		);
+	}
+
+	#[test]
+	fn test_target_tail() {
+		let writer: LoggingWriter = LoggingSubscriberBuilder::default()
+			.with_time(false)
+			.with_target(true)
+			.with_target_tail(2)
+			.into();
+		let now = Local::now();
+
+		assert_eq!(
+			format_target(&writer, "a::b::c::d", "hello", &[], now),
+			"INFO  c::d: hello\n"
+		);
+		assert_eq!(format_target(&writer, "b::c", "hello", &[], now), "INFO  b::c: hello\n");
+		assert_eq!(format_target(&writer, "app", "hello", &[], now), "INFO  app: hello\n");
+	}
+
+	#[test]
+	#[should_panic(expected = "the target tail must keep at least one component")]
+	fn test_target_tail_zero() {
+		let _ = LoggingSubscriberBuilder::default().with_target_tail(0);
+	}
+
+	#[test]
+	fn test_thread_level() {
+		console::set_colors_enabled(false);
+		let sink = ChunkedSink::default();
+		let builder = LoggingSubscriberBuilder::default()
+			.with_time(false)
+			.with_min_level(LevelFilter::INFO)
+			.with_writer(sink.clone());
+
+		let handles: Vec<_> = [("verbose", true), ("quiet", false)]
+			.into_iter()
+			.map(|(name, verbose)| {
+				let mut writer: LoggingWriter = builder.clone().into();
+				thread::spawn(move || {
+					if verbose {
+						set_thread_level(LevelFilter::TRACE);
+					}
+					writer
+						.log(
+							&Record::builder()
+								.args(format_args!("{}", name))
+								.level(log::Level::Trace)
+								.build(),
+						)
+						.unwrap();
+					clear_thread_level();
+					writer
+						.log(
+							&Record::builder()
+								.args(format_args!("{} cleared", name))
+								.level(log::Level::Trace)
+								.build(),
+						)
+						.unwrap();
+				})
+			})
+			.collect();
+		handles.into_iter().for_each(|handle| handle.join().unwrap());
+
+		let output = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+		assert_eq!(output, "TRACE verbose\n");
+	}
+
+	#[test]
+	fn test_timestamp_elapsed_human() {
+		let writer: LoggingWriter = LoggingSubscriberBuilder::default()
+			.with_timestamp_mode(TimestampMode::ElapsedHuman)
+			.into();
+		let start = writer.start;
+
+		assert_eq!(
+			format_info(&writer, "hello", start + chrono::Duration::seconds(3725)),
+			"1h02m05s INFO  hello\n"
+		);
+		assert_eq!(
+			format_info(&writer, "hello", start + chrono::Duration::seconds(754)),
+			"12m34s INFO  hello\n"
+		);
+		assert_eq!(
+			format_info(&writer, "hello", start + chrono::Duration::seconds(7)),
+			"7s INFO  hello\n"
+		);
+	}
+
+	#[test]
+	fn test_field_key_style_for() {
+		let writer: LoggingWriter = LoggingSubscriberBuilder::default()
+			.with_time(false)
+			.with_field_key_style_for("error", Style::new().red().force_styling(true))
+			.into();
+		let fields = [("error", "timeout".to_string()), ("attempt", "3".to_string())];
+		let output = format_info_with_fields(&writer, "request failed", &fields, Local::now());
+		assert_eq!(
+			output,
+			"INFO  request failed \u{1b}[31merror=\u{1b}[0mtimeout attempt=3\n"
+		);
+	}
+
+	#[test]
+	fn test_diagnostics() {
+		console::set_colors_enabled(false);
+		let sink = ChunkedSink::default();
+		let mut writer: LoggingWriter = LoggingSubscriberBuilder::default()
+			.with_time(false)
+			.with_min_level(LevelFilter::DEBUG)
+			.with_diagnostics(true)
+			.with_writer(sink.clone())
+			.into();
+
+		for level in [log::Level::Trace, log::Level::Info] {
+			writer
+				.log(&Record::builder().args(format_args!("hello")).level(level).target("app").build())
+				.unwrap();
+		}
+
+		let output = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+		assert_eq!(
+			output,
+			"[diagnostics] TRACE app: dropped (below level debug)\n[diagnostics] INFO app: passed\nINFO  hello\n"
+		);
+	}
+
+	#[test]
+	fn test_diagnostics_disabled() {
+		console::set_colors_enabled(false);
+		let sink = ChunkedSink::default();
+		let mut writer: LoggingWriter = LoggingSubscriberBuilder::default()
+			.with_time(false)
+			.with_diagnostics(true)
+			.with_writer(sink.clone())
+			.into();
+		writer.enabled = false;
+
+		writer
+			.log(
+				&Record::builder()
+					.args(format_args!("hello"))
+					.level(log::Level::Info)
+					.target("app")
+					.build(),
+			)
+			.unwrap();
+
+		let output = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+		assert_eq!(output, "[diagnostics] INFO app: dropped (disabled)\n");
+	}
+
+	#[test]
+	fn test_slow_span_threshold() {
+		let _guard = GLOBAL_WRITER.lock().unwrap_or_else(|err| err.into_inner());
+		console::set_colors_enabled(false);
+		let sink = ChunkedSink::default();
+		let layer = LoggingSubscriberBuilder::default()
+			.with_time(false)
+			.with_file(true)
+			.with_slow_span_threshold(Duration::from_millis(50))
+			.with_writer(sink.clone())
+			.build();
+
+		subscriber::with_default(Registry::default().with(layer), || {
+			info_span!("fast").in_scope(|| {});
+			info_span!("slow").in_scope(|| thread::sleep(Duration::from_millis(80)));
+		});
+
+		let output = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+		let lines: Vec<&str> = output.lines().collect();
+		assert_eq!(lines.len(), 1);
+		assert!(lines[0].starts_with("WARN  <test.rs>: slow span slow closed busy="));
+		*LOGGING_WRITER.lock().unwrap() = LoggingWriter::default();
+	}
+
+	#[test]
+	fn test_lossy_utf8() {
+		let _guard = GLOBAL_WRITER.lock().unwrap_or_else(|err| err.into_inner());
+		console::set_colors_enabled(false);
+
+		for (lossy_utf8, expected) in [
+			(true, "INFO  bytes payload=café \u{fffd}\n"),
+			(false, "INFO  bytes payload=b\"caf\\xc3\\xa9 \\xff\"\n"),
+		] {
+			let sink = ChunkedSink::default();
+			let layer = LoggingSubscriberBuilder::default()
+				.with_time(false)
+				.with_lossy_utf8(lossy_utf8)
+				.with_writer(sink.clone())
+				.build();
+
+			subscriber::with_default(Registry::default().with(layer), || {
+				info!(payload = &b"caf\xc3\xa9 \xff"[..], "bytes");
+			});
+
+			assert_eq!(String::from_utf8(sink.0.lock().unwrap().clone()).unwrap(), expected);
+		}
+		*LOGGING_WRITER.lock().unwrap() = LoggingWriter::default();
+	}
+
+	#[test]
+	fn test_non_blocking_writer() {
+		let _guard = GLOBAL_WRITER.lock().unwrap_or_else(|err| err.into_inner());
+		console::set_colors_enabled(false);
+		let sink = ChunkedSink::default();
+		let (non_blocking, worker_guard) = tracing_appender::non_blocking(sink.clone());
+		let (layer, worker_guard_out) = LoggingSubscriberBuilder::default()
+			.with_time(false)
+			.with_non_blocking_writer(non_blocking, worker_guard)
+			.build_with_guard();
+		assert!(worker_guard_out.is_some());
+
+		subscriber::with_default(Registry::default().with(layer), || {
+			info!("first");
+			warn!("second");
+		});
+		drop(worker_guard_out);
+
+		let output = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+		assert_eq!(output, "INFO  first\nWARN  second\n");
+		*LOGGING_WRITER.lock().unwrap() = LoggingWriter::default();
+	}
+
+	#[test]
+	fn test_level_precedence() {
+		let mut writer: LoggingWriter = LoggingSubscriberBuilder::default().with_min_level(LevelFilter::TRACE).into();
+		writer.env_level = parse_env_level("warn");
+		writer.runtime_level = Some(LevelFilter::ERROR);
+		assert_eq!(writer.effective_level(), LevelFilter::ERROR);
+
+		writer.runtime_level = None;
+		assert_eq!(writer.effective_level(), LevelFilter::WARN);
+
+		writer.env_level = parse_env_level("my_crate=debug");
+		assert_eq!(writer.effective_level(), LevelFilter::TRACE);
+
+		let _guard = GLOBAL_WRITER.lock().unwrap_or_else(|err| err.into_inner());
+		*LOGGING_WRITER.lock().unwrap() = writer;
+		set_level(LevelFilter::INFO).unwrap();
+		assert_eq!(effective_level().unwrap(), LevelFilter::INFO);
+		clear_level().unwrap();
+		assert_eq!(effective_level().unwrap(), LevelFilter::TRACE);
+		*LOGGING_WRITER.lock().unwrap() = LoggingWriter::default();
+	}
+
+	#[test]
+	fn test_min_level_env_override() {
+		let _guard = GLOBAL_WRITER.lock().unwrap_or_else(|err| err.into_inner());
+		let previous = std::env::var_os("RUST_LOG");
+		std::env::set_var("RUST_LOG", "warn");
+
+		let builder = LoggingSubscriberBuilder::default().with_min_level(LevelFilter::TRACE);
+		let writer: LoggingWriter = builder.clone().with_min_level_env_override(true).into();
+		assert_eq!(writer.effective_level(), LevelFilter::TRACE);
+
+		let _layer = builder.clone().build();
+		assert_eq!(effective_level().unwrap(), LevelFilter::TRACE);
+
+		let _layer = builder.with_min_level_env_override(true).build();
+		assert_eq!(effective_level().unwrap(), LevelFilter::WARN);
+		set_level(LevelFilter::ERROR).unwrap();
+		assert_eq!(effective_level().unwrap(), LevelFilter::ERROR);
+
+		match previous {
+			Some(previous) => std::env::set_var("RUST_LOG", previous),
+			None => std::env::remove_var("RUST_LOG"),
+		}
+		*LOGGING_WRITER.lock().unwrap() = LoggingWriter::default();
+	}
+
+	#[test]
+	fn test_render_cache() {
+		let cached: LoggingWriter = LoggingSubscriberBuilder::default()
+			.with_time(false)
+			.with_render_cache(true)
+			.into();
+		let uncached: LoggingWriter = LoggingSubscriberBuilder::default().with_time(false).into();
+		let fields = [("attempt", "1".to_string())];
+
+		let first = format_info_with_fields(&cached, "retrying", &fields, Local::now());
+		assert!(cached.last_render.borrow().is_some());
+		let second = format_info_with_fields(&cached, "retrying", &fields, Local::now());
+		assert_eq!(first, second);
+		assert_eq!(
+			second,
+			format_info_with_fields(&uncached, "retrying", &fields, Local::now())
+		);
+
+		// a hit reuses the cached body as is
+		cached.last_render.borrow_mut().as_mut().unwrap().1 = "from cache".to_string();
+		assert_eq!(
+			format_info_with_fields(&cached, "retrying", &fields, Local::now()),
+			"from cache\n"
+		);
+
+		let fields = [("attempt", "2".to_string())];
+		assert_eq!(
+			format_info_with_fields(&cached, "retrying", &fields, Local::now()),
+			format_info_with_fields(&uncached, "retrying", &fields, Local::now())
+		);
+
+		let resolved: LoggingWriter = LoggingSubscriberBuilder::default()
+			.with_time(false)
+			.with_render_cache(true)
+			.with_style_resolver(Arc::new(|_| StyleSet::default()))
+			.into();
+		format_info_with_fields(&resolved, "retrying", &fields, Local::now());
+		assert!(resolved.last_render.borrow().is_none());
+	}
+
+	#[test]
+	fn test_heartbeat() {
+		let _guard = GLOBAL_WRITER.lock().unwrap_or_else(|err| err.into_inner());
+		console::set_colors_enabled(false);
+		let sink = ChunkedSink::default();
+		let layer = LoggingSubscriberBuilder::default()
+			.with_time(false)
+			.with_heartbeat(Duration::from_millis(50))
+			.with_writer(sink.clone())
+			.build();
+		// the heartbeat thread only sees the global default; test_simple may have installed an equivalent one already
+		let _ = subscriber::set_global_default(Registry::default().with(layer));
+
+		thread::sleep(Duration::from_millis(200));
+		shutdown().unwrap();
+		let output = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+		assert!(output.lines().count() >= 2);
+		assert!(output.lines().all(|line| line == "INFO  -- still running --"));
+
+		thread::sleep(Duration::from_millis(120));
+		assert_eq!(sink.0.lock().unwrap().len(), output.len());
+		*LOGGING_WRITER.lock().unwrap() = LoggingWriter::default();
+	}
+
+	#[test]
+	#[should_panic(expected = "the heartbeat interval must not be zero")]
+	fn test_heartbeat_zero_interval() {
+		let _ = LoggingSubscriberBuilder::default().with_heartbeat(Duration::ZERO);
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn test_color_strip_for_redirected_stdout() {
+		let null = std::fs::OpenOptions::new().read(true).write(true).open("/dev/null").unwrap();
+		let redirected = console::Term::read_write_pair(null.try_clone().unwrap(), null);
+		assert!(!ansi_supported(&redirected));
+
+		let builder = LoggingSubscriberBuilder::default()
+			.with_time(false)
+			.with_color_strip_for_redirected_stdout(true);
+		let record = Record::builder()
+			.args(format_args!("plain"))
+			.level(log::Level::Info)
+			.target("test")
+			.build();
+
+		let mut writer: LoggingWriter = builder.clone().into();
+		writer.force_styling(ansi_supported(&redirected));
+		assert_eq!(writer.format_event(&record, &[], Local::now()), "INFO  plain\n");
+
+		let mut writer: LoggingWriter = builder.into();
+		writer.force_styling(true);
+		assert!(writer.format_event(&record, &[], Local::now()).contains('\x1b'));
+	}
+
+	#[test]
+	fn test_color_strip_with_writer() {
+		let _guard = GLOBAL_WRITER.lock().unwrap_or_else(|err| err.into_inner());
+		console::set_colors_enabled(true);
+		let sink = ChunkedSink::default();
+		let layer = LoggingSubscriberBuilder::default()
+			.with_time(false)
+			.with_color_strip_for_redirected_stdout(true)
+			.with_writer(sink.clone())
+			.build();
+
+		subscriber::with_default(Registry::default().with(layer), || info!("plain"));
+		console::set_colors_enabled(false);
+
+		let output = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+		assert_eq!(output, "INFO  plain\n");
+		*LOGGING_WRITER.lock().unwrap() = LoggingWriter::default();
+	}
+
+	#[test]
+	fn test_panicking_field_transform() {
+		let _guard = GLOBAL_WRITER.lock().unwrap_or_else(|err| err.into_inner());
+		console::set_colors_enabled(false);
+		let sink = ChunkedSink::default();
+		let layer = LoggingSubscriberBuilder::default()
+			.with_time(false)
+			.with_field_transform(Arc::new(|_, value| match value {
+				"bad" => panic!("bad field"),
+				_ => value.to_string(),
+			}))
+			.with_writer(sink.clone())
+			.build();
+
+		subscriber::with_default(Registry::default().with(layer), || {
+			assert!(std::panic::catch_unwind(|| info!(a = "bad", "first")).is_err());
+			info!(a = "good", "second");
+		});
+
+		let output = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+		assert_eq!(output, "INFO  second a=good\n");
+		*LOGGING_WRITER.lock().unwrap_or_else(|err| err.into_inner()) = LoggingWriter::default();
+	}
+
+	#[test]
+	fn test_non_blocking_writer_shutdown() {
+		let _guard = GLOBAL_WRITER.lock().unwrap_or_else(|err| err.into_inner());
+		console::set_colors_enabled(false);
+		let sink = ChunkedSink::default();
+		let (non_blocking, worker_guard) = tracing_appender::non_blocking(sink.clone());
+		let builder = LoggingSubscriberBuilder::default()
+			.with_time(false)
+			.with_non_blocking_writer(non_blocking, worker_guard);
+		// converting a clone must leave the guard, and so the worker, alone
+		drop(LoggingWriter::from(builder.clone()));
+		let layer = builder.build();
+
+		subscriber::with_default(Registry::default().with(layer), || {
+			for index in 0..100 {
+				info!("line {}", index);
+			}
+		});
+		shutdown().unwrap();
+
+		let output = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+		let expected: String = (0..100).map(|index| format!("INFO  line {}\n", index)).collect();
+		assert_eq!(output, expected);
+		*LOGGING_WRITER.lock().unwrap() = LoggingWriter::default();
+	}
+
+	#[test]
+	fn test_shutdown_with_panicking_flush() {
+		/// Sink panicking when flushed.
+		struct PanicOnFlushSink;
+
+		impl Write for PanicOnFlushSink {
+			fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+				Ok(buf.len())
+			}
+
+			fn flush(&mut self) -> std::io::Result<()> {
+				panic!("broken flush");
+			}
+		}
+
+		let _guard = GLOBAL_WRITER.lock().unwrap_or_else(|err| err.into_inner());
+		let layer = LoggingSubscriberBuilder::default()
+			.with_line_buffered(false)
+			.with_writer(PanicOnFlushSink)
+			.build();
+
+		// the hook logs the panic while shutdown holds the writer, which must not deadlock
+		let result = with_panic_to_tracing(|| {
+			subscriber::with_default(Registry::default().with(layer), || std::panic::catch_unwind(shutdown))
+		});
+		assert!(result.is_err());
+		*LOGGING_WRITER.lock().unwrap_or_else(|err| err.into_inner()) = LoggingWriter::default();
+	}
 }